@@ -6,12 +6,13 @@ use crate::{
 use crate::protocol::{self, light_client_handler, message::Roles, CustomMessageOutcome, Protocol};
 use libp2p::NetworkBehaviour;
 use libp2p::core::{Multiaddr, PeerId, PublicKey};
-use libp2p::kad::record;
+use libp2p::kad::{record, Quorum};
 use libp2p::swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters};
 use log::debug;
+use prometheus_endpoint::{register, CounterVec, Gauge, GaugeVec, Opts, PrometheusError, Registry, U64};
 use sp_consensus::{BlockOrigin, import_queue::{IncomingBlock, Origin}};
 use sp_runtime::{traits::{Block as BlockT, NumberFor}, ConsensusEngineId, Justification};
-use std::{borrow::Cow, iter, task::Context, task::Poll};
+use std::{borrow::Cow, collections::HashMap, iter, task::Context, task::Poll, time::Duration};
 use void;
 
 /// General behaviour of the network. Combines all protocols together.
@@ -37,6 +38,16 @@ pub struct Behaviour<B: BlockT, H: ExHashT> {
 	/// Role of our local node, as originally passed from the configuration.
 	#[behaviour(ignore)]
 	role: Role,
+
+	/// Human-readable protocol name of every notifications protocol that was registered,
+	/// keyed by `engine_id`. Used to enrich `NotificationStreamOpened`/`Closed` events and
+	/// metric labels with something more useful than a raw `ConsensusEngineId`.
+	#[behaviour(ignore)]
+	notification_protocol_names: HashMap<ConsensusEngineId, Cow<'static, str>>,
+
+	/// Prometheus metrics, if we were asked to register some.
+	#[behaviour(ignore)]
+	metrics: Option<Metrics>,
 }
 
 /// Event generated by `Behaviour`.
@@ -49,8 +60,160 @@ pub enum BehaviourOut<B: BlockT> {
 	Event(Event),
 }
 
+/// Configuration for a [`Behaviour::get_value_with`] call.
+#[derive(Debug, Clone, Default)]
+pub struct GetConfig {
+	/// Number of peers that must return the record before `ValueFound` fires. `None` means the
+	/// Kademlia default quorum.
+	pub quorum: Option<Quorum>,
+}
+
+/// Configuration for a [`Behaviour::put_value_with`] call.
+#[derive(Debug, Clone, Default)]
+pub struct PutConfig {
+	/// Number of closest peers that must acknowledge storing the record before `ValuePut` fires.
+	/// `None` means the Kademlia default quorum.
+	pub quorum: Option<Quorum>,
+	/// How long remote stores should keep the record before it expires. `None` means the
+	/// Kademlia default record TTL.
+	pub ttl: Option<Duration>,
+}
+
+impl<B: BlockT> BehaviourOut<B> {
+	/// Short, stable label identifying this variant, for use in metric label values.
+	fn as_metrics_label(&self) -> &'static str {
+		match self {
+			BehaviourOut::BlockImport(..) => "block-import",
+			BehaviourOut::JustificationImport(..) => "justification-import",
+			BehaviourOut::FinalityProofImport(..) => "finality-proof-import",
+			BehaviourOut::RandomKademliaStarted(..) => "random-kademlia-started",
+			BehaviourOut::Event(..) => "event",
+		}
+	}
+}
+
+/// Prometheus metrics for the combined [`Behaviour`].
+struct Metrics {
+	/// Number of entries in the Kademlia k-buckets, per `ProtocolId`.
+	kbuckets_entries: GaugeVec<U64>,
+	/// Number of records in the Kademlia record stores, per `ProtocolId`.
+	kademlia_records: GaugeVec<U64>,
+	/// Total size in bytes of the Kademlia record stores, per `ProtocolId`.
+	kademlia_records_total_size: GaugeVec<U64>,
+	/// Number of times we started a random Kademlia discovery query, per `ProtocolId`.
+	random_kademlia_started_total: CounterVec<U64>,
+	/// Number of Kademlia `get`/`put` queries, broken down by outcome.
+	kademlia_query_outcomes_total: CounterVec<U64>,
+	/// Number of notifications substreams opened and closed, per protocol name.
+	notifications_streams_total: CounterVec<U64>,
+	/// Number of `BehaviourOut` events produced, broken down by variant.
+	events_total: CounterVec<U64>,
+	/// Current interval between random Kademlia discovery queries, as adapted by the discovery
+	/// scheduler to the current peer count.
+	random_kademlia_interval_seconds: Gauge<U64>,
+	/// Target number of routing-table peers the random Kademlia walk scheduler aims for before
+	/// it backs off and pauses querying.
+	random_kademlia_peers_target: Gauge<U64>,
+}
+
+impl Metrics {
+	fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+		Ok(Self {
+			kbuckets_entries: register(
+				GaugeVec::new(
+					Opts::new(
+						"sub_libp2p_kbuckets_entries",
+						"Number of entries in the Kademlia k-buckets",
+					),
+					&["protocol"],
+				)?,
+				registry,
+			)?,
+			kademlia_records: register(
+				GaugeVec::new(
+					Opts::new(
+						"sub_libp2p_kademlia_records_count",
+						"Number of records in the Kademlia record stores",
+					),
+					&["protocol"],
+				)?,
+				registry,
+			)?,
+			kademlia_records_total_size: register(
+				GaugeVec::new(
+					Opts::new(
+						"sub_libp2p_kademlia_records_sizes_total",
+						"Total size in bytes of the records in the Kademlia record stores",
+					),
+					&["protocol"],
+				)?,
+				registry,
+			)?,
+			random_kademlia_started_total: register(
+				CounterVec::new(
+					Opts::new(
+						"sub_libp2p_random_kademlia_started_total",
+						"Number of times a random Kademlia discovery query has been started",
+					),
+					&["protocol"],
+				)?,
+				registry,
+			)?,
+			kademlia_query_outcomes_total: register(
+				CounterVec::new(
+					Opts::new(
+						"sub_libp2p_kademlia_query_outcomes_total",
+						"Number of Kademlia put/get queries, by outcome",
+					),
+					&["outcome"],
+				)?,
+				registry,
+			)?,
+			notifications_streams_total: register(
+				CounterVec::new(
+					Opts::new(
+						"sub_libp2p_notifications_streams_total",
+						"Number of notifications substreams opened and closed",
+					),
+					&["protocol", "action"],
+				)?,
+				registry,
+			)?,
+			events_total: register(
+				CounterVec::new(
+					Opts::new(
+						"sub_libp2p_behaviour_events_total",
+						"Number of events produced by the network behaviour, by kind",
+					),
+					&["kind"],
+				)?,
+				registry,
+			)?,
+			random_kademlia_interval_seconds: register(
+				Gauge::new(
+					"sub_libp2p_random_kademlia_interval_seconds",
+					"Current interval between random Kademlia discovery queries",
+				)?,
+				registry,
+			)?,
+			random_kademlia_peers_target: register(
+				Gauge::new(
+					"sub_libp2p_random_kademlia_peers_target",
+					"Target number of routing-table peers the random Kademlia walk scheduler aims for",
+				)?,
+				registry,
+			)?,
+		})
+	}
+}
+
 impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 	/// Builds a new `Behaviour`.
+	///
+	/// `metrics_registry` is an optional Prometheus registry. If one is passed, the metrics of
+	/// this behaviour are registered with it; the registry is not passed on to the protocols it
+	/// wraps. Nodes that don't run a Prometheus endpoint can pass `None` and pay no overhead for
+	/// metrics collection.
 	pub fn new(
 		substrate: Protocol<B, H>,
 		role: Role,
@@ -59,8 +222,11 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 		block_requests: protocol::BlockRequests<B>,
 		light_client_handler: protocol::LightClientHandler<B>,
 		disco_config: DiscoveryConfig,
-	) -> Self {
-		Behaviour {
+		metrics_registry: Option<&Registry>,
+	) -> Result<Self, PrometheusError> {
+		let metrics = metrics_registry.map(Metrics::register).transpose()?;
+
+		Ok(Behaviour {
 			substrate,
 			debug_info: debug_info::DebugInfoBehaviour::new(user_agent, local_public_key.clone()),
 			discovery: disco_config.finish(),
@@ -68,7 +234,9 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 			light_client_handler,
 			events: Vec::new(),
 			role,
-		}
+			notification_protocol_names: HashMap::new(),
+			metrics,
+		})
 	}
 
 	/// Returns the list of nodes that we know exist in the network.
@@ -96,6 +264,18 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 		self.discovery.kademlia_records_total_size()
 	}
 
+	/// Returns the current interval between random Kademlia discovery queries, as adapted by
+	/// the discovery scheduler to how well-connected we currently are.
+	pub fn random_kademlia_interval(&self) -> Duration {
+		self.discovery.random_kademlia_interval()
+	}
+
+	/// Returns the target number of routing-table peers the random Kademlia walk scheduler
+	/// aims for before it backs off and pauses querying.
+	pub fn random_kademlia_peers_target(&self) -> usize {
+		self.discovery.random_kademlia_peers_target()
+	}
+
 	/// Borrows `self` and returns a struct giving access to the information about a node.
 	///
 	/// Returns `None` if we don't know anything about this node. Always returns `Some` for nodes
@@ -117,18 +297,54 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 	pub fn register_notifications_protocol(
 		&mut self,
 		engine_id: ConsensusEngineId,
-		protocol_name: impl Into<Cow<'static, [u8]>>,
+		protocol_name: impl Into<Cow<'static, str>>,
 	) {
-		let list = self.substrate.register_notifications_protocol(engine_id, protocol_name);
+		let protocol_name = protocol_name.into();
+		assert!(
+			protocol_name.chars().all(|c| !c.is_control()),
+			"notifications protocol name must be printable UTF-8, got {:?}", protocol_name,
+		);
+
+		let list = self.substrate.register_notifications_protocol(engine_id, protocol_name.clone());
 		for (remote, roles) in list {
 			let role = reported_roles_to_observed_role(&self.role, remote, roles);
 			let ev = Event::NotificationStreamOpened {
 				remote: remote.clone(),
 				engine_id,
+				protocol_name: protocol_name.clone(),
 				role,
 			};
+			if let Some(metrics) = &self.metrics {
+				metrics.notifications_streams_total
+					.with_label_values(&[&protocol_name, "opened"])
+					.inc();
+			}
 			self.events.push(BehaviourOut::Event(ev));
 		}
+
+		self.notification_protocol_names.insert(engine_id, protocol_name);
+	}
+
+	/// Returns the human-readable protocol name registered for `engine_id`, or a best-effort
+	/// rendering of the raw engine id if none was registered (which shouldn't normally happen).
+	fn protocol_name_or_fallback(&self, engine_id: &ConsensusEngineId) -> Cow<'static, str> {
+		self.notification_protocol_names.get(engine_id)
+			.cloned()
+			.unwrap_or_else(|| Cow::Owned(engine_id_label(engine_id)))
+	}
+
+	/// Obtains a [`NotificationSender`] for a connected peer and a given notifications protocol.
+	///
+	/// Unlike `write_notifications`, which silently buffers or drops messages when the peer's
+	/// send queue is full, the sender requires the caller to first await a free slot with
+	/// [`NotificationSender::ready`] before sending. This lets producers naturally slow down to
+	/// the speed of the slowest relevant peer instead of piling up memory or losing messages.
+	pub fn notification_sender(
+		&self,
+		peer_id: PeerId,
+		engine_id: ConsensusEngineId,
+	) -> Result<protocol::NotificationSender, protocol::NotificationSenderError> {
+		self.substrate.notification_sender(peer_id, engine_id)
 	}
 
 	/// Returns a shared reference to the user protocol.
@@ -143,12 +359,25 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 
 	/// Start querying a record from the DHT. Will later produce either a `ValueFound` or a `ValueNotFound` event.
 	pub fn get_value(&mut self, key: &record::Key) {
-		self.discovery.get_value(key);
+		self.get_value_with(key, GetConfig::default())
+	}
+
+	/// Same as [`Behaviour::get_value`], but lets the caller tune the read quorum instead of
+	/// relying on the Kademlia default.
+	pub fn get_value_with(&mut self, key: &record::Key, config: GetConfig) {
+		self.discovery.get_value(key, config.quorum);
 	}
 
 	/// Starts putting a record into DHT. Will later produce either a `ValuePut` or a `ValuePutFailed` event.
 	pub fn put_value(&mut self, key: record::Key, value: Vec<u8>) {
-		self.discovery.put_value(key, value);
+		self.put_value_with(key, value, PutConfig::default())
+	}
+
+	/// Same as [`Behaviour::put_value`], but lets the caller tune the write quorum and the
+	/// record TTL instead of relying on the Kademlia defaults. Useful for applications such as
+	/// authority discovery that want to trade off write durability against put latency.
+	pub fn put_value_with(&mut self, key: record::Key, value: Vec<u8>, config: PutConfig) {
+		self.discovery.put_value(key, value, config.quorum, config.ttl);
 	}
 
 	/// Issue a light client request.
@@ -157,6 +386,13 @@ impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 	}
 }
 
+/// Renders a `ConsensusEngineId` as a short string label, replacing bytes that are not valid
+/// UTF-8 with `U+FFFD`. Used as a fallback for notification
+/// events and metric labels when no human-readable protocol name was registered for it.
+fn engine_id_label(engine_id: &ConsensusEngineId) -> String {
+	String::from_utf8_lossy(engine_id).into_owned()
+}
+
 fn reported_roles_to_observed_role(local_role: &Role, remote: &PeerId, roles: Roles) -> ObservedRole {
 	if roles.is_authority() {
 		match local_role {
@@ -193,18 +429,32 @@ Behaviour<B, H> {
 			CustomMessageOutcome::NotificationStreamOpened { remote, protocols, roles } => {
 				let role = reported_roles_to_observed_role(&self.role, &remote, roles);
 				for engine_id in protocols {
+					let protocol_name = self.protocol_name_or_fallback(&engine_id);
+					if let Some(metrics) = &self.metrics {
+						metrics.notifications_streams_total
+							.with_label_values(&[&protocol_name, "opened"])
+							.inc();
+					}
 					self.events.push(BehaviourOut::Event(Event::NotificationStreamOpened {
 						remote: remote.clone(),
 						engine_id,
+						protocol_name,
 						role: role.clone(),
 					}));
 				}
 			},
 			CustomMessageOutcome::NotificationStreamClosed { remote, protocols } =>
 				for engine_id in protocols {
+					let protocol_name = self.protocol_name_or_fallback(&engine_id);
+					if let Some(metrics) = &self.metrics {
+						metrics.notifications_streams_total
+							.with_label_values(&[&protocol_name, "closed"])
+							.inc();
+					}
 					self.events.push(BehaviourOut::Event(Event::NotificationStreamClosed {
 						remote: remote.clone(),
 						engine_id,
+						protocol_name,
 					}));
 				},
 			CustomMessageOutcome::NotificationsReceived { remote, messages } => {
@@ -231,7 +481,11 @@ impl<B: BlockT, H: ExHashT> NetworkBehaviourEventProcess<debug_info::DebugInfoEv
 			info.listen_addrs.truncate(30);
 		}
 		for addr in &info.listen_addrs {
-			self.discovery.add_self_reported_address(&peer_id, addr.clone());
+			self.discovery.add_self_reported_address(
+				&peer_id,
+				info.protocols.iter().map(|p| p.as_bytes()),
+				addr.clone(),
+			);
 		}
 		self.substrate.add_discovered_nodes(iter::once(peer_id.clone()));
 	}
@@ -251,19 +505,36 @@ impl<B: BlockT, H: ExHashT> NetworkBehaviourEventProcess<DiscoveryOut>
 				self.substrate.add_discovered_nodes(iter::once(peer_id));
 			}
 			DiscoveryOut::ValueFound(results) => {
+				if let Some(metrics) = &self.metrics {
+					metrics.kademlia_query_outcomes_total.with_label_values(&["value-found"]).inc();
+				}
 				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValueFound(results))));
 			}
 			DiscoveryOut::ValueNotFound(key) => {
+				if let Some(metrics) = &self.metrics {
+					metrics.kademlia_query_outcomes_total.with_label_values(&["value-not-found"]).inc();
+				}
 				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValueNotFound(key))));
 			}
-			DiscoveryOut::ValuePut(key) => {
-				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValuePut(key))));
+			DiscoveryOut::ValuePut(key, num_peers_stored) => {
+				if let Some(metrics) = &self.metrics {
+					metrics.kademlia_query_outcomes_total.with_label_values(&["value-put"]).inc();
+				}
+				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValuePut(key, num_peers_stored))));
 			}
-			DiscoveryOut::ValuePutFailed(key) => {
-				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValuePutFailed(key))));
+			DiscoveryOut::ValuePutFailed(key, num_peers_stored) => {
+				if let Some(metrics) = &self.metrics {
+					metrics.kademlia_query_outcomes_total.with_label_values(&["value-put-failed"]).inc();
+				}
+				self.events.push(BehaviourOut::Event(Event::Dht(DhtEvent::ValuePutFailed(key, num_peers_stored))));
 			}
 			DiscoveryOut::RandomKademliaStarted(protocols) => {
 				for protocol in protocols {
+					if let Some(metrics) = &self.metrics {
+						metrics.random_kademlia_started_total
+							.with_label_values(&[protocol.as_ref()])
+							.inc();
+					}
 					self.events.push(BehaviourOut::RandomKademliaStarted(protocol));
 				}
 			}
@@ -273,8 +544,26 @@ impl<B: BlockT, H: ExHashT> NetworkBehaviourEventProcess<DiscoveryOut>
 
 impl<B: BlockT, H: ExHashT> Behaviour<B, H> {
 	fn poll<TEv>(&mut self, _: &mut Context, _: &mut impl PollParameters) -> Poll<NetworkBehaviourAction<TEv, BehaviourOut<B>>> {
+		if let Some(metrics) = &self.metrics {
+			for (protocol, entries) in self.discovery.num_kbuckets_entries() {
+				metrics.kbuckets_entries.with_label_values(&[protocol.as_ref()]).set(entries as u64);
+			}
+			for (protocol, records) in self.discovery.num_kademlia_records() {
+				metrics.kademlia_records.with_label_values(&[protocol.as_ref()]).set(records as u64);
+			}
+			for (protocol, size) in self.discovery.kademlia_records_total_size() {
+				metrics.kademlia_records_total_size.with_label_values(&[protocol.as_ref()]).set(size as u64);
+			}
+			metrics.random_kademlia_interval_seconds.set(self.discovery.random_kademlia_interval().as_secs());
+			metrics.random_kademlia_peers_target.set(self.discovery.random_kademlia_peers_target() as u64);
+		}
+
 		if !self.events.is_empty() {
-			return Poll::Ready(NetworkBehaviourAction::GenerateEvent(self.events.remove(0)))
+			let event = self.events.remove(0);
+			if let Some(metrics) = &self.metrics {
+				metrics.events_total.with_label_values(&[event.as_metrics_label()]).inc();
+			}
+			return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event))
 		}
 
 		Poll::Pending